@@ -1,96 +1,113 @@
+mod fmt;
+
+use fmt::FormatStyle;
+
+/// `UserCode` is backed by its lines rather than one flat `String` so
+/// that `del` and `append` only ever touch the lines they actually
+/// change, instead of rebuilding the whole buffer on every edit.
+/// `rendered` caches `lines.join("\n")` lazily, since that join is the
+/// one operation that does need to walk the whole buffer; it is reset
+/// by every mutation and recomputed at most once per call to `as_ref`.
 #[derive(Debug)]
-pub struct UserCode(String);
+pub struct UserCode {
+    lines: Vec<String>,
+    n_opened: i32,
+    style: FormatStyle,
+    rendered: OnceCell<String>,
+}
 
 impl UserCode {
     pub fn new<S>(source: S) -> Self
     where
         S: AsRef<str>,
     {
-        UserCode(String::from(source.as_ref()))
+        let lines: Vec<String> = source.as_ref().lines().map(String::from).collect();
+        UserCode {
+            n_opened: count_opened(&lines.join("\n")),
+            lines,
+            style: FormatStyle::default(),
+            rendered: OnceCell::new(),
+        }
+    }
+
+    /// Reformat future (and the current) buffer contents using `style`
+    /// instead of the default.
+    pub fn set_style(&mut self, style: FormatStyle) {
+        self.style = style;
+        self.format();
     }
 
+    /// Append raw lines to the end of the buffer. This only touches the
+    /// new tail lines and the running paren count; it does not reformat
+    /// anything, so call `format` explicitly once the buffer is balanced.
     pub fn append<S>(&mut self, source: S)
     where
         S: DiscordCode,
     {
-        let indents = match self.balance() {
-            Balanced::NoMissing(n) => n as usize,
-            _ => 0,
-        };
-
         let code = source.strip_discord_code();
         for line in code.lines() {
-            let mut chars = line.chars();
-            while let Some(c) = chars.next() {
-                match c {
-                    ')' => self.0.push(')'),
-                    _ => {
-                        if self.0.ends_with(')') {
-                            self.0.push('\n');
-                        }
-
-                        // This hacky way of adding tabs seems to be a good
-                        // heuristic for making the code look decent while
-                        // begin fast.
-                        self.0.push_str(&"\t".repeat(indents));
-
-                        self.0.push(c);
-                        self.0.push_str(&chars.collect::<String>());
-                        break;
-                    },
-                }
-            }
+            self.n_opened += count_opened(line);
+            self.lines.push(line.to_owned());
+        }
+        self.rendered = OnceCell::new();
+    }
+
+    /// Re-indent the whole buffer from its parsed structure, the way
+    /// rustfmt rebuilds layout from the AST instead of patching text.
+    ///
+    /// If the buffer doesn't parse (unbalanced parens, most likely
+    /// because the user is still mid-form), the tail is left untouched
+    /// so they can keep typing incrementally.
+    pub fn format(&mut self) {
+        if !matches!(self.balance(), Balanced::Yes) {
+            return;
         }
+
+        let source = self.lines.join("\n");
+        let forms = match parse(&source).collect::<Result<Vec<_>, _>>() {
+            Ok(forms) => forms,
+            Err(_) => return,
+        };
+        let rendered = forms
+            .iter()
+            .map(|form| fmt::render(form, self.style))
+            .collect::<Vec<_>>()
+            .join("\n");
+        self.n_opened = count_opened(&rendered);
+        self.lines = rendered.lines().map(String::from).collect();
+        self.rendered = OnceCell::new();
     }
 
     /// Delete lines by index. `0` deletes the last line and
-    /// `1` deletes the line before that etc..
+    /// `1` deletes the line before that etc.. Returns `None` both for
+    /// a negative index and for one that is out of range (unlike the
+    /// original flat-`String` version, which silently deleted line `0`
+    /// for any out-of-range index).
     pub fn del(&mut self, del_idx: i64) -> Option<String> {
-        let effective_idx;
-        if !del_idx.is_negative() {
-            effective_idx =
-                self.0.lines().count().saturating_sub(del_idx as usize + 1);
-        } else {
+        if del_idx.is_negative() {
             return None;
         }
+        let effective_idx =
+            self.lines.len().checked_sub(del_idx as usize + 1)?;
 
-        let mut deleted = None;
-        self.0 = self
-            .0
-            .lines()
-            .enumerate()
-            .filter_map(|(idx, line)| {
-                if idx != effective_idx {
-                    Some(format!("{}\n", line))
-                } else {
-                    deleted = Some(line.to_owned());
-                    None
-                }
-            })
-            .collect::<String>();
-        deleted
+        let deleted = self.lines.remove(effective_idx);
+        self.n_opened -= count_opened(&deleted);
+        self.rendered = OnceCell::new();
+        Some(deleted)
     }
 
     /// Are the parentheses in the source code balanced?
     fn balance(&self) -> Balanced {
-        let mut n_opened: i32 = 0;
-        for c in self.0.chars() {
-            match c {
-                '(' => n_opened += 1,
-                ')' => n_opened -= 1,
-                _ => {},
-            }
-        }
-        match n_opened {
+        match self.n_opened {
             0 => Balanced::Yes,
-            i32::MIN..=-1 => Balanced::NoTrailing(n_opened.abs() as u32),
-            1..=i32::MAX => Balanced::NoMissing(n_opened as u32),
+            i32::MIN..=-1 => Balanced::NoTrailing(self.n_opened.unsigned_abs()),
+            1..=i32::MAX => Balanced::NoMissing(self.n_opened as u32),
         }
     }
 
     fn eval(&self) -> String {
         let mut env = LisEnv::new();
-        for sexpr in parse(&self.0) {
+        for sexpr in parse(self.as_ref()) {
             if let Ok(value) = sexpr {
                 env.eval(&value);
             }
@@ -114,8 +131,22 @@ impl UserCode {
 
 impl AsRef<str> for UserCode {
     fn as_ref(&self) -> &str {
-        &self.0
+        let Self { lines, rendered, .. } = self;
+        rendered.get_or_init(|| lines.join("\n"))
+    }
+}
+
+/// Count net open parens in `s`, positive for more `(` than `)`.
+fn count_opened(s: &str) -> i32 {
+    let mut n_opened = 0;
+    for c in s.chars() {
+        match c {
+            '(' => n_opened += 1,
+            ')' => n_opened -= 1,
+            _ => {},
+        }
     }
+    n_opened
 }
 
 #[derive(Debug)]
@@ -228,7 +259,7 @@ impl std::fmt::Display for LisEnv {
     }
 }
 
-use std::cell::RefCell;
+use std::cell::{OnceCell, RefCell};
 use std::fmt::Write;
 use std::rc::Rc;
 
@@ -256,15 +287,81 @@ mod tests {
     }
 
     #[test]
-    fn append_code_works() {
-        let mut code = UserCode::new(
-            "(define fib (lambda (n)\n\t\t(if (< n 2)\n\t\t\tn(+ (fib (- n 1))",
-        );
-        code.append("(fib (- n 2))");
-        assert!(code.0.ends_with("\n\t\t\t\t(fib (- n 2))"));
-        code.append(")");
-        assert!(code.0.ends_with("(- n 2)))"));
-        code.append(")))");
-        assert!(code.0.ends_with("(- n 2))))))"));
+    fn append_only_adds_lines_until_format_is_called() {
+        let mut code = UserCode::new("(define x 1)");
+        code.append("(define y 2)");
+        assert_eq!(code.as_ref(), "(define x 1)\n(define y 2)");
+
+        code.format();
+        assert_eq!(code.as_ref(), "(define x 1)\n(define y 2)");
+    }
+
+    #[test]
+    fn format_leaves_unbalanced_buffer_untouched() {
+        let mut code = UserCode::new("(define fib (lambda (n)");
+        code.append("(if (< n 2) n");
+        code.format();
+        assert_eq!(code.as_ref(), "(define fib (lambda (n)\n(if (< n 2) n");
+    }
+
+    #[test]
+    fn aligned_style_lines_up_under_first_argument() {
+        let long_form = "(cond (a-very-long-condition-one x) \
+             (a-very-long-condition-two y) (a-very-long-condition-three z))";
+        let mut code = UserCode::new(long_form);
+        code.set_style(FormatStyle::Aligned);
+
+        let indent = " ".repeat("(cond ".len());
+        assert!(code.as_ref().lines().nth(1).unwrap().starts_with(&indent));
+    }
+
+    #[test]
+    fn aligned_style_carries_its_column_into_nested_breaks() {
+        // The inner `cond` is too long for one line and is placed under
+        // the already-broken `let`, so its continuation clauses must align
+        // under its first argument at its real column, not at the column
+        // they would get if the `cond` started at column 0.
+        let long_form = "(let ((a-very-long-binding-name-one 111111) \
+             (a-very-long-binding-name-two 222222)) \
+             (cond (a-very-long-condition-one x) \
+             (a-very-long-condition-two y) (a-very-long-condition-three z)))";
+        let mut code = UserCode::new(long_form);
+        code.set_style(FormatStyle::Aligned);
+
+        let rendered = code.as_ref();
+        let indent_of = |line: &str| line.len() - line.trim_start().len();
+        let cond_clause_indents: Vec<usize> = rendered
+            .lines()
+            .filter(|line| line.trim_start().starts_with("(a-very-long-condition"))
+            .map(indent_of)
+            .collect();
+
+        assert_eq!(cond_clause_indents.len(), 2);
+        assert_eq!(cond_clause_indents[0], cond_clause_indents[1]);
+        assert!(cond_clause_indents[0] > "(cond ".len());
+    }
+
+    #[test]
+    fn del_removes_line_by_reverse_index() {
+        let mut code = UserCode::new("(define x 1)\n(define y 2)\n(define z 3)");
+        assert_eq!(code.del(0).as_deref(), Some("(define z 3)"));
+        assert_eq!(code.as_ref(), "(define x 1)\n(define y 2)");
+        assert_eq!(code.del(1).as_deref(), Some("(define x 1)"));
+        assert_eq!(code.as_ref(), "(define y 2)");
+    }
+
+    #[test]
+    fn del_tracks_balance_of_the_removed_line() {
+        let mut code = UserCode::new("(define x 1)\n(foo");
+        assert!(matches!(code.balance(), Balanced::NoMissing(1)));
+        code.del(0);
+        assert!(matches!(code.balance(), Balanced::Yes));
+    }
+
+    #[test]
+    fn del_out_of_range_leaves_buffer_untouched() {
+        let mut code = UserCode::new("(define x 1)\n(define y 2)");
+        assert_eq!(code.del(5), None);
+        assert_eq!(code.as_ref(), "(define x 1)\n(define y 2)");
     }
 }
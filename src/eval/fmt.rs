@@ -0,0 +1,123 @@
+//! A small width-aware pretty-printer for Lisp forms.
+//!
+//! Instead of patching whitespace into the raw source as it comes in,
+//! this renders a parsed `Value` back into text: a list is printed flat
+//! on one line if it fits within `MAX_WIDTH`, otherwise it is broken
+//! across lines with the operator kept on the open-paren line; where the
+//! break falls and how the following lines are indented is set by
+//! `FormatStyle`.
+
+use rust_lisp::model::Value;
+
+/// Column budget before a form is broken across multiple lines.
+pub const MAX_WIDTH: usize = 80;
+
+/// Spaces added per nesting level when `FormatStyle::Flat` breaks a form.
+const INDENT_WIDTH: usize = 2;
+
+/// How the arguments of a broken form line up once it no longer fits
+/// on one line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FormatStyle {
+    /// Each continuation line sits `INDENT_WIDTH` spaces further right
+    /// than the line the form itself starts on, regardless of how long
+    /// the operator is.
+    #[default]
+    Flat,
+    /// Continuation lines line up under the column of the first
+    /// argument, e.g. `(cond (a b)` puts the next clause flush under
+    /// `(a b)`.
+    Aligned,
+}
+
+/// Render a single top-level form, as if it started at column 0.
+pub fn render(value: &Value, style: FormatStyle) -> String {
+    render_at(value, 0, style)
+}
+
+/// Render `value` assuming it starts printing at column `col`, so a
+/// nested broken form can align itself relative to where it actually
+/// ends up on the page rather than relative to column 0.
+fn render_at(value: &Value, col: usize, style: FormatStyle) -> String {
+    match value {
+        Value::List(list) => {
+            render_list(&list.into_iter().collect::<Vec<_>>(), col, style)
+        },
+        atom => atom.to_string(),
+    }
+}
+
+fn render_list(elems: &[Value], col: usize, style: FormatStyle) -> String {
+    if elems.is_empty() {
+        return "()".to_owned();
+    }
+
+    // Measure the fully flat rendering (never itself broken) to decide
+    // whether this form fits; using `render_at` here would let an
+    // oversize child break *inside* the candidate, which is not a
+    // single line at all.
+    let flat = render_flat_list(elems);
+    if col + flat.len() <= MAX_WIDTH {
+        return flat;
+    }
+
+    // The operator always stays on the open-paren line, per Lisp
+    // convention, so it is rendered flat too.
+    let op = render_flat(&elems[0]);
+
+    match style {
+        FormatStyle::Flat => {
+            let child_col = col + INDENT_WIDTH;
+            let indent = " ".repeat(child_col);
+
+            let mut broken = format!("({op}");
+            for elem in &elems[1..] {
+                broken.push('\n');
+                broken.push_str(&indent);
+                broken.push_str(&render_at(elem, child_col, style));
+            }
+            broken.push(')');
+            broken
+        },
+        FormatStyle::Aligned => {
+            // With fewer than two elements there is no first argument
+            // to align anything under; the flat form already covers it.
+            if elems.len() < 2 {
+                return format!("({op})");
+            }
+
+            // Keep the first argument on the open-paren line and align
+            // every later clause under the column it starts at.
+            let first_col = col + 1 + op.len() + 1;
+            let first = render_at(&elems[1], first_col, style);
+            let indent = " ".repeat(first_col);
+
+            let mut broken = format!("({op} {first}");
+            for elem in &elems[2..] {
+                broken.push('\n');
+                broken.push_str(&indent);
+                broken.push_str(&render_at(elem, first_col, style));
+            }
+            broken.push(')');
+            broken
+        },
+    }
+}
+
+/// Render `value` on a single line, ignoring `MAX_WIDTH` entirely.
+fn render_flat(value: &Value) -> String {
+    match value {
+        Value::List(list) => render_flat_list(&list.into_iter().collect::<Vec<_>>()),
+        atom => atom.to_string(),
+    }
+}
+
+fn render_flat_list(elems: &[Value]) -> String {
+    if elems.is_empty() {
+        return "()".to_owned();
+    }
+    format!(
+        "({})",
+        elems.iter().map(render_flat).collect::<Vec<_>>().join(" ")
+    )
+}